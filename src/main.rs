@@ -1,71 +1,133 @@
 // etherhosts: create hosts and ethers files from CSV
 // By David Atkinson 2021
-// CSV parsing is primitive, but should handle quoted strings
+// CSV parsing is RFC 4180 compliant: quoted fields may contain commas,
+// newlines, and escaped "" quotes
 
+use std::collections::HashMap;
 use std::env;
-use std::fs; 
+use std::fs;
+use std::io::Read;
+use std::net::IpAddr;
 use chrono::{DateTime, Local};
+use flate2::read::GzDecoder;
+use ipnet::IpNet;
 use regex::Regex;
 
-fn process_csv_line(txt: &str) -> Vec<String> {                  
-    // This function splits a CSV line at commas, and handles basic quoted text
-    // It does not handle multiline text
-    
-    // Make a copy of the input string
-    let mut line = String::from(txt);
-    
-    // A "" should be translated to a single quote
-    // Replace "" with space, and store the location
-    let mut ddq = Vec::<usize>::new();   
-    let mut f = line.find("\"\"");
-    while f.is_some() {
-        let i = f.unwrap();
-        ddq.push(i);
-        line.replace_range(i..=i+1, " ");
-        f = line.find("\"\"");
-    }
+// gzip magic bytes
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Reads the input file, transparently decompressing it if it's gzipped (detected
+// by a ".gz" suffix on the filename, or by the gzip magic bytes, so compressed
+// input works even when renamed).
+fn read_input(path: &str) -> String {
+	let bytes = fs::read(path).expect("Unable to open input file");
+
+	let is_gzip = path.ends_with(".gz") || bytes.starts_with(&GZIP_MAGIC);
+
+	if is_gzip {
+		let mut decoded = String::new();
+		GzDecoder::new(&bytes[..])
+			.read_to_string(&mut decoded)
+			.expect("Unable to decompress gzip input file");
+		decoded
+	} else {
+		String::from_utf8(bytes).expect("Input file is not valid UTF-8")
+	}
+}
+
+// Parses a whole CSV document into records of cells, per RFC 4180: a field is
+// either a bare run of characters (no comma or newline), or a double-quoted
+// string in which a comma, a newline, or an escaped "" (a literal quote) may
+// appear; records are terminated by a bare "\n" or "\r\n" outside quotes (a
+// lone "\r" outside quotes also ends a record).
+//
+// Each record is paired with the 1-indexed physical line on which it starts,
+// since a quoted field may embed newlines and so span more lines than records.
+fn parse_csv(input: &str) -> Vec<(usize, Vec<String>)> {
+    let mut records = Vec::<(usize, Vec<String>)>::new();
+    let mut record = Vec::<String>::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line_no: usize = 1;
+    let mut record_start_line: usize = 1;
 
-    // Now there is only single (bounding) quotes and commas, it's easier to parse
-    // e.g. cell1,"cell2, with comma",cell3    
-    let mut quoted: bool = false;
-    let mut cells = Vec::<String>::new();
-    let mut cell = String::new();
-    
-    for (i, b) in line.into_bytes().iter().enumerate() {
-        let c = *b as char;
-        if c == '"' {
-            quoted = !quoted;
-        } else if !quoted && c == ',' {
-            cells.push(cell.clone());
-            cell.clear();
-        } else if ddq.contains(&i) {    // put the " back in
-            cell.push('"');
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    // escaped "" -> literal "
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            } else {
+                if c == '\n' {
+                    line_no += 1;
+                }
+                field.push(c);
+            }
         } else {
-            cell.push(c);
+            match c {
+                '"' => quoted = true,
+                ',' => {
+                    record.push(field.clone());
+                    field.clear();
+                },
+                '\r' => {
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    record.push(field.clone());
+                    field.clear();
+                    records.push((record_start_line, record.clone()));
+                    record.clear();
+                    line_no += 1;
+                    record_start_line = line_no;
+                },
+                '\n' => {
+                    record.push(field.clone());
+                    field.clear();
+                    records.push((record_start_line, record.clone()));
+                    record.clear();
+                    line_no += 1;
+                    record_start_line = line_no;
+                },
+                _ => field.push(c),
+            }
         }
     }
 
-    cells.push(cell);
-    
-    return cells;
+    // the last record won't be terminated by a newline if the file doesn't end with one
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_start_line, record));
+    }
+
+    records
 }
 
 fn clean_ipaddr(s: &str) -> Result<String, String>  {
-	// This function checks (and performs minor cleaning of) an ipv4 dotted decimal address.
+	// This function checks (and performs minor cleaning of) an ipv4 or ipv6 address.
 	// It returns an Ok(String) for a valid ipaddr, and
-	// Err(String) for an erroneous (or missing) address.
-	
+	// Err("") for a blank ipaddr, and
+	// Err(String) for an erroneous address.
+
 	// trim both sides of any extra whitespace
 	let s = s.trim();
-	
-	// check it matches a basic ipv4 dotted decimal pattern
-	let re = Regex::new(r"^((25[0-5]|(2[0-4]|1\d|[1-9]|)\d)(\.)){3}(25[0-5]|(2[0-4]|1\d|[1-9]|)\d)$").unwrap();	
 
-	// return a copy of the ipaddr if it matches, otherwise return an error
-	if re.is_match(s) {
-		return Ok(s.to_string());
-	} else {
-		return Err("ipaddr failed regex check".to_string());
+	// if the ipaddr is empty, return a blank string as err
+	if s.len() == 0 {
+	    return Err("".to_string());
+	}
+
+	// parse into std::net::IpAddr, which accepts both ipv4 dotted decimal and
+	// ipv6 (including compressed "::" notation); to_string() then gives us
+	// the canonical, lowercase, zero-compressed form for free
+	match s.parse::<IpAddr>() {
+		Ok(addr) => Ok(addr.to_string()),
+		Err(_)   => Err("ipaddr failed to parse".to_string()),
 	}
 }
 
@@ -130,10 +192,289 @@ fn clean_hostname(s: &str) -> Result<String, String> {
 	}
 }
 
+// A single line of a `hosts` file, once parsed.
+enum HostsPart {
+	Entry(IpAddr, Vec<String>, Option<String>),          // ipaddr, hostname + aliases, trailing comment
+	CommentedEntry(IpAddr, Vec<String>, Option<String>), // an entry that has been commented out
+	Comment(String),                                     // a standalone comment line
+	Blank,                                                // a blank line
+	Other(String),                                        // anything we can't make sense of, kept verbatim
+}
+
+// Tries to read "ipaddr name [alias ...] [# comment]" out of a hosts line body
+// (the part after any leading "#" has already been stripped by the caller).
+fn parse_hosts_entry(s: &str) -> Option<(IpAddr, Vec<String>, Option<String>)> {
+	let (main_part, comment) = match s.find('#') {
+		Some(i) => (s[..i].trim(), Some(s[i+1..].trim().to_string())),
+		None    => (s.trim(), None),
+	};
+
+	let mut fields = main_part.split_whitespace();
+	let ipaddr = fields.next()?.parse::<IpAddr>().ok()?;
+	let names: Vec<String> = fields.map(|f| f.to_string()).collect();
+
+	if names.is_empty() {
+		return None;
+	}
+
+	Some((ipaddr, names, comment))
+}
+
+fn parse_hosts_part(line: &str) -> HostsPart {
+	let trimmed = line.trim();
+
+	if trimmed.is_empty() {
+		return HostsPart::Blank;
+	}
+
+	if let Some(rest) = trimmed.strip_prefix('#') {
+		let rest = rest.trim();
+		return match parse_hosts_entry(rest) {
+			Some((ipaddr, names, comment)) => HostsPart::CommentedEntry(ipaddr, names, comment),
+			None                           => HostsPart::Comment(rest.to_string()),
+		};
+	}
+
+	match parse_hosts_entry(trimmed) {
+		Some((ipaddr, names, comment)) => HostsPart::Entry(ipaddr, names, comment),
+		None                           => HostsPart::Other(line.to_string()),
+	}
+}
+
+fn format_hosts_part(part: &HostsPart) -> String {
+	match part {
+		HostsPart::Entry(ipaddr, names, comment) => match comment {
+			Some(c) => format!("{} {} # {}\n", ipaddr, names.join(" "), c),
+			None    => format!("{} {}\n", ipaddr, names.join(" ")),
+		},
+		HostsPart::CommentedEntry(ipaddr, names, comment) => match comment {
+			Some(c) => format!("# {} {} # {}\n", ipaddr, names.join(" "), c),
+			None    => format!("# {} {}\n", ipaddr, names.join(" ")),
+		},
+		HostsPart::Comment(text) => format!("# {}\n", text),
+		HostsPart::Blank         => "\n".to_string(),
+		HostsPart::Other(raw)    => format!("{}\n", raw),
+	}
+}
+
+// Merges freshly generated (ipaddr, names) entries into an existing hosts file,
+// updating only the lines etherhosts already owns (those whose ipaddr matches
+// one of `new_entries`) and leaving everything else untouched and in place.
+// Any new entries with no existing line are appended under `header`.
+fn merge_hosts_entries(existing: &str, new_entries: &[(IpAddr, Vec<String>)], header: &str, header_marker: &str) -> String {
+	let lines: Vec<&str> = existing.lines().collect();
+	let mut parts: Vec<HostsPart> = lines.iter().map(|line| parse_hosts_part(line)).collect();
+	let mut updated = vec![false; parts.len()];
+	let mut remaining: Vec<(IpAddr, Vec<String>)> = new_entries.to_vec();
+
+	for (i, part) in parts.iter_mut().enumerate() {
+		let pos = match part {
+			HostsPart::Entry(ipaddr, _, _) => remaining.iter().position(|(ip, _)| ip == ipaddr),
+			_ => None,
+		};
+		if let Some(pos) = pos {
+			let (ipaddr, names) = remaining.remove(pos);
+			// keep whatever trailing comment a sysadmin had already put on this line
+			let comment = match part {
+				HostsPart::Entry(_, _, comment) => comment.take(),
+				_                               => None,
+			};
+			*part = HostsPart::Entry(ipaddr, names, comment);
+			updated[i] = true;
+		}
+	}
+
+	// a previous run's header block is kept around as a plain Comment on re-parse;
+	// reuse it rather than stacking another timestamped header on every run that
+	// happens to add a new host
+	let already_has_header = parts.iter().any(|part| matches!(part, HostsPart::Comment(text) if text.starts_with(header_marker)));
+
+	// lines that weren't updated are written back exactly as they were read
+	let mut out = String::new();
+	for (i, part) in parts.iter().enumerate() {
+		if updated[i] {
+			out.push_str(&format_hosts_part(part));
+		} else {
+			out.push_str(lines[i]);
+			out.push('\n');
+		}
+	}
+
+	if !remaining.is_empty() {
+		if !already_has_header {
+			out.push_str(header);
+		}
+		for (ipaddr, names) in &remaining {
+			out.push_str(&format_hosts_part(&HostsPart::Entry(*ipaddr, names.clone(), None)));
+		}
+	}
+
+	out
+}
+
+// A single line of an `ethers` file, once parsed.
+enum EthersPart {
+	Entry(String, IpAddr), // macaddr, ipaddr
+	Comment(String),
+	Blank,
+	Other(String),
+}
+
+fn parse_ethers_part(line: &str) -> EthersPart {
+	let trimmed = line.trim();
+
+	if trimmed.is_empty() {
+		return EthersPart::Blank;
+	}
+
+	if let Some(rest) = trimmed.strip_prefix('#') {
+		return EthersPart::Comment(rest.trim().to_string());
+	}
+
+	let mut fields = trimmed.split_whitespace();
+	let entry = match (fields.next(), fields.next()) {
+		(Some(macaddr), Some(ip_str)) => ip_str.parse::<IpAddr>().ok().map(|ipaddr| (macaddr.to_string(), ipaddr)),
+		_ => None,
+	};
+
+	match entry {
+		Some((macaddr, ipaddr)) => EthersPart::Entry(macaddr, ipaddr),
+		None                    => EthersPart::Other(line.to_string()),
+	}
+}
+
+fn format_ethers_part(part: &EthersPart) -> String {
+	match part {
+		EthersPart::Entry(macaddr, ipaddr) => format!("{} {}\n", macaddr, ipaddr),
+		EthersPart::Comment(text)          => format!("# {}\n", text),
+		EthersPart::Blank                  => "\n".to_string(),
+		EthersPart::Other(raw)             => format!("{}\n", raw),
+	}
+}
+
+// Same idea as merge_hosts_entries, but keyed on macaddr since that's what
+// identifies an `ethers` line.
+fn merge_ethers_entries(existing: &str, new_entries: &[(String, IpAddr)], header: &str, header_marker: &str) -> String {
+	let lines: Vec<&str> = existing.lines().collect();
+	let mut parts: Vec<EthersPart> = lines.iter().map(|line| parse_ethers_part(line)).collect();
+	let mut updated = vec![false; parts.len()];
+	let mut remaining: Vec<(String, IpAddr)> = new_entries.to_vec();
+
+	for (i, part) in parts.iter_mut().enumerate() {
+		let pos = match part {
+			EthersPart::Entry(macaddr, _) => remaining.iter().position(|(mac, _)| mac == macaddr),
+			_ => None,
+		};
+		if let Some(pos) = pos {
+			let (macaddr, ipaddr) = remaining.remove(pos);
+			*part = EthersPart::Entry(macaddr, ipaddr);
+			updated[i] = true;
+		}
+	}
+
+	// a previous run's header block is kept around as a plain Comment on re-parse;
+	// reuse it rather than stacking another timestamped header on every run that
+	// happens to add a new mac/ip pairing
+	let already_has_header = parts.iter().any(|part| matches!(part, EthersPart::Comment(text) if text.starts_with(header_marker)));
+
+	// lines that weren't updated are written back exactly as they were read
+	let mut out = String::new();
+	for (i, part) in parts.iter().enumerate() {
+		if updated[i] {
+			out.push_str(&format_ethers_part(part));
+		} else {
+			out.push_str(lines[i]);
+			out.push('\n');
+		}
+	}
+
+	if !remaining.is_empty() {
+		if !already_has_header {
+			out.push_str(header);
+		}
+		for (macaddr, ipaddr) in &remaining {
+			out.push_str(&format_ethers_part(&EthersPart::Entry(macaddr.clone(), *ipaddr)));
+		}
+	}
+
+	out
+}
+
+// Matches additional alias columns like "alias2", "alias3", etc.
+fn is_numbered_alias_column(field: &str) -> bool {
+	match field.strip_prefix("alias") {
+		Some(suffix) => !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()),
+		None         => false,
+	}
+}
+
+// Sorts hosts entries numerically by ipaddr, removes exact duplicates, and warns
+// if the same hostname ends up mapped to more than one ipaddr.
+fn sort_dedup_hosts_entries(entries: &mut Vec<(IpAddr, Vec<String>)>) {
+	entries.sort();
+	entries.dedup();
+
+	// two rows for the same ipaddr but with different hostnames/aliases sort
+	// next to each other but aren't equal, so dedup() above won't catch them;
+	// fold them into a single entry rather than emitting duplicate hosts lines
+	let mut merged = Vec::<(IpAddr, Vec<String>)>::new();
+	for (ipaddr, names) in entries.drain(..) {
+		match merged.last_mut() {
+			Some((last_ip, last_names)) if *last_ip == ipaddr => {
+				let mut gained_names = false;
+				for name in names {
+					if !last_names.contains(&name) {
+						last_names.push(name);
+						gained_names = true;
+					}
+				}
+				if gained_names {
+					println!("warning: ipaddr {} appears multiple times with different hostnames, merged into one entry: {}", ipaddr, last_names.join(" "));
+				}
+			},
+			_ => merged.push((ipaddr, names)),
+		}
+	}
+	*entries = merged;
+
+	let mut ips_by_name = HashMap::<&String, Vec<IpAddr>>::new();
+	for (ipaddr, names) in entries.iter() {
+		for name in names {
+			ips_by_name.entry(name).or_default().push(*ipaddr);
+		}
+	}
+
+	for (name, ips) in &ips_by_name {
+		if ips.len() > 1 {
+			let ips: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+			println!("warning: hostname {} is mapped to multiple addresses: {}", name, ips.join(", "));
+		}
+	}
+}
+
+// Sorts ethers entries numerically by ipaddr, removes exact duplicates, and warns
+// if the same ipaddr ends up mapped to more than one macaddr.
+fn sort_dedup_ethers_entries(entries: &mut Vec<(String, IpAddr)>) {
+	entries.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+	entries.dedup();
+
+	let mut macs_by_ip = HashMap::<IpAddr, Vec<&String>>::new();
+	for (macaddr, ipaddr) in entries.iter() {
+		macs_by_ip.entry(*ipaddr).or_default().push(macaddr);
+	}
+
+	for (ipaddr, macs) in &macs_by_ip {
+		if macs.len() > 1 {
+			let macs: Vec<&str> = macs.iter().map(|m| m.as_str()).collect();
+			println!("warning: ipaddr {} is mapped to multiple macaddrs: {}", ipaddr, macs.join(", "));
+		}
+	}
+}
+
 fn main() {
     // display program info
     println!("Etherhosts: Create hosts and ethers files from CSV");
-    println!("Usage: etherhosts [etherhosts.csv] [hosts] [ethers]");
+    println!("Usage: etherhosts [etherhosts.csv] [hosts] [ethers] [subnet ...]");
 
     // filenames
     let mut inputfile = "etherhosts.csv";
@@ -152,15 +493,30 @@ fn main() {
         ethersfile = &args[3];
     }
 
-    // read input file
-    let input = fs::read_to_string(inputfile).expect("Unable to open input file");
-    let mut lines = input.lines();
+    // any remaining arguments are an allowlist of CIDR subnets; when given, only
+    // addresses falling within one of them are emitted
+    let mut subnets = Vec::<IpNet>::new();
+    for arg in args.iter().skip(4) {
+        match arg.parse::<IpNet>() {
+            Ok(net) => subnets.push(net),
+            Err(e)  => {
+                println!("Couldn't parse '{}' as a subnet: {}", arg, e);
+                return;
+            }
+        }
+    }
+
+    // read input file, transparently decompressing it if it's gzipped
+    let input = read_input(inputfile);
+    let mut records = parse_csv(&input).into_iter();
 
-    // read first line to determine positions of each column
-    let header_row = process_csv_line(&lines.next().expect("Input file didn't have a single line!").to_string());
+    // read first record to determine positions of each column
+    let (_, header_row) = records.next().expect("Input file didn't have a single record!");
     let mut ipaddrcol: usize = 0;
     let mut hostnamecol: usize = 0;
     let mut maccol: usize = 0;
+    let mut aliasescol: Option<usize> = None;
+    let mut aliascols = Vec::<usize>::new();
     let mut found_i = false;
     let mut found_h = false;
     let mut found_m = false;
@@ -175,6 +531,10 @@ fn main() {
         } else if field == "macaddr" {
             found_m = true;
             maccol = c;
+        } else if field == "aliases" {
+            aliasescol = Some(c);
+        } else if field == "alias" || is_numbered_alias_column(field) {
+            aliascols.push(c);
         }
     }
 
@@ -186,59 +546,116 @@ fn main() {
     // display our input and output file names
     println!("Input csv:     {}\nOutput hosts:  {}\nOutput ethers: {}", inputfile, hostsfile, ethersfile);
 
-    // hosts and ethers to be stored in strings
-    let mut hoststxt = String::new();
-    let mut etherstxt = String::new();
+    // entries collected from the CSV, to be merged into the hosts/ethers files
+    let mut hosts_entries = Vec::<(IpAddr, Vec<String>)>::new();
+    let mut ethers_entries = Vec::<(String, IpAddr)>::new();
 
-    // header for hosts and ethers files
+    // header for newly added hosts and ethers entries; the marker text (without
+    // the timestamp) is used to recognize a header block a previous run already
+    // left in place, so repeat runs don't keep stacking new header blocks
     let now: DateTime<Local> = Local::now();
     let timestr = now.format("%F %T %Z");
 
-    hoststxt.push_str(&format!("# hosts automatically generated by etherhosts {}\n", &timestr));
-    etherstxt.push_str(&format!("# ethers automatically generated by etherhosts {}\n", &timestr));
-
-    // process each line of the input file
-    for (r,line) in lines.enumerate() {
-        let fields = process_csv_line(&line.to_string());
+    let hosts_header_marker = "hosts entries added by etherhosts";
+    let ethers_header_marker = "ethers entries added by etherhosts";
+    let hosts_header = format!("# {} {}\n", hosts_header_marker, &timestr);
+    let ethers_header = format!("# {} {}\n", ethers_header_marker, &timestr);
 
+    // process each record of the input file
+    for (line, fields) in records {
 	let ipaddr = match clean_ipaddr(&fields[ipaddrcol]) {
 	    Ok(s)  => s,
 	    Err(s) => {
-		println!("skipping line {}: {}", r+2, s);
+		println!("skipping line {}: {}", line, s);
 		continue;
 	    }
 	};
 		
+	// the ipaddr was already validated above, so this parse can't fail
+	let parsed_ipaddr: IpAddr = ipaddr.parse().unwrap();
+
+	if !subnets.is_empty() && !subnets.iter().any(|net| net.contains(&parsed_ipaddr)) {
+	    println!("skipping line {}: ipaddr {} is not in any configured subnet", line, parsed_ipaddr);
+	    continue;
+	}
+
 	match clean_hostname(&fields[hostnamecol]) {
 	    Ok(hostname)  => {
+		// the canonical name goes first, followed by any valid aliases
+		let mut names = vec![hostname];
+
+		for &c in &aliascols {
+		    // a row may be shorter than the header if trailing alias cells were omitted;
+		    // treat a missing cell the same as a blank one
+		    match clean_hostname(fields.get(c).map_or("", |f| f.as_str())) {
+			Ok(alias) => names.push(alias),
+			Err(s) => {
+			    if s.len() != 0 {
+				println!("invalid alias on line {}: {}", line, s);
+			    }
+			}
+		    }
+		}
+
+		if let Some(c) = aliasescol {
+		    for alias in fields.get(c).map_or("", |f| f.as_str()).split_whitespace() {
+			match clean_hostname(alias) {
+			    Ok(alias) => names.push(alias),
+			    Err(s) => {
+				if s.len() != 0 {
+				    println!("invalid alias on line {}: {}", line, s);
+				}
+			    }
+			}
+		    }
+		}
+
 		// add to hosts
-		// ipaddr can be padded using {: <15}
-		let hostline = format!("{} {}\n", ipaddr, hostname);
-		hoststxt.push_str(&hostline);
+		hosts_entries.push((parsed_ipaddr, names));
 	    },
 	    Err(s) => {
 		// If the string is empty, it's simply a blank hostname and not a real error
 		if s.len() != 0 {
-		    println!("invalid hostname on line {}: {}", r+2, s);
+		    println!("invalid hostname on line {}: {}", line, s);
 		}
 	    }
 	}
 	    
-	match clean_mac(&fields[maccol]) {
-	    Ok(macaddr)  => {
-		// add to ethers
-		let etherline = format!("{} {}\n", macaddr, ipaddr);
-		etherstxt.push_str(&etherline);
-	    },
-	    Err(s) => {
-		// If the string is empty, it's simply a blank macaddr and not a real error
-		if s.len() != 0 {
-		    println!("invalid macaddr on line {}: {}", r+2, s);
-		}
-	    }
+	// ethers is ipv4-only, so skip ipv6 addresses rather than treating them as an error
+	if parsed_ipaddr.is_ipv6() {
+	    println!("skipping ethers entry on line {}: ethers is ipv4-only, address is ipv6", line);
+	} else {
+	    match clean_mac(&fields[maccol]) {
+	        Ok(macaddr)  => {
+		    // add to ethers
+		    ethers_entries.push((macaddr, parsed_ipaddr));
+	        },
+	        Err(s) => {
+		    // If the string is empty, it's simply a blank macaddr and not a real error
+		    if s.len() != 0 {
+		        println!("invalid macaddr on line {}: {}", line, s);
+		    }
+	        }
+            }
         }
     }
 
+    // sort numerically by ipaddr, drop exact duplicates, and warn on conflicts
+    // before writing anything out
+    sort_dedup_hosts_entries(&mut hosts_entries);
+    sort_dedup_ethers_entries(&mut ethers_entries);
+
+    // merge into the existing hosts/ethers files if present, otherwise start fresh;
+    // this makes it safe to run etherhosts repeatedly against a real /etc/hosts
+    let hoststxt = match fs::read_to_string(hostsfile) {
+        Ok(existing) => merge_hosts_entries(&existing, &hosts_entries, &hosts_header, hosts_header_marker),
+        Err(_)       => merge_hosts_entries("", &hosts_entries, &hosts_header, hosts_header_marker),
+    };
+    let etherstxt = match fs::read_to_string(ethersfile) {
+        Ok(existing) => merge_ethers_entries(&existing, &ethers_entries, &ethers_header, ethers_header_marker),
+        Err(_)       => merge_ethers_entries("", &ethers_entries, &ethers_header, ethers_header_marker),
+    };
+
     // write to output files
     if fs::write(hostsfile, hoststxt).is_err() {
         println!("Unable to write to hosts file");